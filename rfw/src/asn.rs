@@ -0,0 +1,71 @@
+// ASN (自治系统号) 数据加载
+//
+// 与国家粒度的 GeoIP 规则互补: 使用 MaxMind GeoLite2-ASN 风格的 .mmdb 数据库，
+// 将 IP 前缀映射到其所属 ASN，配合 `--block-asn AS13335,AS32934` 精确屏蔽
+// Cloudflare/AWS/Facebook 等不以国家划分的云/CDN 网段。和 GeoIP 一样是双栈的:
+// GeoLite2-ASN 数据库同时收录 IPv4/IPv6 前缀，缺一不可，否则像 Cloudflare/Facebook
+// 这类有大量 IPv6 网段的 ASN 只会被屏蔽掉 v4 流量，v6 完全放行。
+
+use std::path::Path;
+
+use anyhow::Context as _;
+
+/// 解析 `--block-asn` 传入的 "AS13335,AS32934" 列表为纯数字 ASN 集合
+pub fn parse_blocked_asn_list(raw: &[String]) -> anyhow::Result<Vec<u32>> {
+    raw.iter()
+        .map(|entry| {
+            let digits = entry
+                .trim()
+                .trim_start_matches("AS")
+                .trim_start_matches("as");
+            digits
+                .parse::<u32>()
+                .with_context(|| format!("无法解析 ASN '{}', 期望格式如 AS13335", entry))
+        })
+        .collect()
+}
+
+/// 从 GeoLite2-ASN 风格的 .mmdb 数据库中提取的 (网络地址, 前缀长度, ASN) 三元组，
+/// 按地址族分开存放，分别写入 ASN_MAP / ASN_MAP6
+#[derive(Debug, Default)]
+pub struct AsnPrefixes {
+    pub v4: Vec<(u32, u32, u32)>,
+    pub v6: Vec<(u128, u32, u32)>,
+}
+
+/// 从 GeoLite2-ASN 风格的 .mmdb 数据库中提取所有 IPv4/IPv6 前缀及其所属 ASN
+pub fn load_asn_prefixes(path: &Path) -> anyhow::Result<AsnPrefixes> {
+    let reader = maxminddb::Reader::open_readfile(path)
+        .with_context(|| format!("打开 ASN 数据库失败: {}", path.display()))?;
+
+    let mut prefixes = AsnPrefixes::default();
+
+    let all_v4: ipnetwork::IpNetwork = "0.0.0.0/0".parse().unwrap();
+    for item in reader.within::<maxminddb::geoip2::Asn>(all_v4)? {
+        let item = item?;
+        let Some(asn) = item.info.autonomous_system_number else {
+            continue;
+        };
+
+        if let ipnetwork::IpNetwork::V4(net) = item.ip_net {
+            prefixes
+                .v4
+                .push((u32::from(net.network()), net.prefix() as u32, asn));
+        }
+    }
+
+    let all_v6: ipnetwork::IpNetwork = "::/0".parse().unwrap();
+    for item in reader.within::<maxminddb::geoip2::Asn>(all_v6)? {
+        let item = item?;
+        let Some(asn) = item.info.autonomous_system_number else {
+            continue;
+        };
+
+        if let ipnetwork::IpNetwork::V6(net) = item.ip_net {
+            let network_ip = u128::from_be_bytes(net.network().octets());
+            prefixes.v6.push((network_ip, net.prefix() as u32, asn));
+        }
+    }
+
+    Ok(prefixes)
+}
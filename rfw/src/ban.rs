@@ -0,0 +1,108 @@
+// 动态封禁 (fail2ban 风格)
+//
+// 内核侧在收到超过 `--ban-threshold` 个 SYN (在 `--ban-window` 秒滑动窗口内) 时，
+// 将源 IP 连同封禁时间写入 BANNED / BAN_TIMESTAMP，XDP 快速路径优先查询 BANNED
+// 并直接丢弃。用户态这里只负责周期性清扫：把封禁时间超过 `--ban-duration` 的
+// 条目从两张表中移除，让封禁自动到期，避免永久占用 map 容量。
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use aya::maps::{Array, HashMap as AyaHashMap, LpmTrie};
+use aya::Ebpf;
+use log::{info, warn};
+use tokio::sync::Mutex;
+
+/// 将 `--ban-threshold` / `--ban-window` / `--ban-duration` 写入 BAN_CONFIG
+pub async fn configure(
+    ebpf: &Arc<Mutex<Ebpf>>,
+    threshold: u32,
+    window_secs: u64,
+    duration_secs: u64,
+) -> anyhow::Result<()> {
+    let mut guard = ebpf.lock().await;
+    let mut ban_config: Array<_, u64> = guard.map_mut("BAN_CONFIG").unwrap().try_into()?;
+    ban_config.set(0, threshold as u64, 0)?;
+    ban_config.set(1, window_secs, 0)?;
+    ban_config.set(2, duration_secs, 0)?;
+    info!(
+        "启用规则: 自动封禁 (阈值 {} 次/{}s 窗口, 封禁 {}s 后自动解封)",
+        threshold, window_secs, duration_secs
+    );
+    Ok(())
+}
+
+/// 启动后台清扫任务，定期驱逐到期的封禁条目
+pub fn spawn_sweep_task(ebpf: Arc<Mutex<Ebpf>>, ban_duration: Duration) {
+    tokio::task::spawn(async move {
+        // 清扫间隔不需要和封禁时长一样精确，取较小值即可及时解封；
+        // `--ban-duration 0` 时不能把 tick 夹到 0，tokio::time::interval(ZERO) 会直接 panic
+        let tick = Duration::from_secs(10)
+            .min(ban_duration)
+            .max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(tick);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep_once(&ebpf, ban_duration).await {
+                warn!("封禁清扫失败: {}", e);
+            }
+        }
+    });
+}
+
+/// 读取与 eBPF 程序里 `bpf_ktime_get_boot_ns()` 同源的时钟(开机以来的纳秒数)。
+/// `BAN_TIMESTAMP` 写入的是内核侧的 ktime，不能拿 `SystemTime::now()` 这种墙钟
+/// (UNIX 纪元以来)去减，两者原点不同，相减恒为巨大正数会导致封禁立即被判定过期
+fn boot_time_ns() -> anyhow::Result<u64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    if ret != 0 {
+        anyhow::bail!(
+            "clock_gettime(CLOCK_BOOTTIME) 失败: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+async fn sweep_once(ebpf: &Arc<Mutex<Ebpf>>, ban_duration: Duration) -> anyhow::Result<()> {
+    let now_ns = boot_time_ns()?;
+    let ttl_ns = ban_duration.as_nanos() as u64;
+
+    let mut guard = ebpf.lock().await;
+
+    let expired: Vec<u32> = {
+        let timestamps: AyaHashMap<_, u32, u64> =
+            guard.map_mut("BAN_TIMESTAMP").unwrap().try_into()?;
+        timestamps
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, banned_at_ns)| now_ns.saturating_sub(*banned_at_ns) > ttl_ns)
+            .map(|(ip, _)| ip)
+            .collect()
+    };
+
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let mut timestamps: AyaHashMap<_, u32, u64> =
+        guard.map_mut("BAN_TIMESTAMP").unwrap().try_into()?;
+    for ip in &expired {
+        let _ = timestamps.remove(ip);
+    }
+    drop(timestamps);
+
+    let mut banned: LpmTrie<_, u32, u8> = guard.map_mut("BANNED").unwrap().try_into()?;
+    for ip in &expired {
+        let key = aya::maps::lpm_trie::Key::new(32, ip.to_be());
+        let _ = banned.remove(&key);
+        info!("封禁已到期，解封 {}", std::net::Ipv4Addr::from(ip.to_be()));
+    }
+
+    info!("本轮清扫解封 {} 个 IP", expired.len());
+    Ok(())
+}
@@ -0,0 +1,451 @@
+// 规则与 GeoIP/ASN 配置的统一装配
+//
+// 启动时的首次加载和 SIGHUP 热重载（见 main.rs）复用同一份 `apply_config`：
+// 根据当前 `Opt` 展开出一份声明式规则列表(`rules` 模块，--config 文件中的规则
+// 加上旧 flag 展开的规则)，重新计算 config_flags、重新拉取/解析 GeoIP 与 ASN
+// 数据、重新编译 PORT_RULES 系列 map，并整体替换对应的 eBPF map 内容。这份合并
+// 后的规则列表就是热重载的最小单元。XDP 程序全程保持附加状态不被卸载，但
+// GeoIP/ASN 的 LpmTrie 是先清空旧条目再逐条重新插入的，不是原子替换：在两者
+// 之间有一段窗口，此时旧前缀已经移除、新前缀还没插完，期间命中这些前缀的包
+// 按"未命中"处理。调用方通过 `last_geo_asn` 传入上一次的 GeoIP/ASN 特征值
+// ([`GeoAsnSignature`])，国家/ASN 集合连同每个国家/ASN 对应的规则作用域 bit
+// 掩码都和上次完全一致时才跳过重新拉取和重建，既省开销也缩小这个窗口出现的
+// 频率；--config 规则文件改了规则顺序/分组导致 bit 重新分配时，即使集合没变
+// 也会让签名失配、照常重建，不会和 PORT_RULE_GEO_GROUP/PORT_RULE_ASN_GROUP 对不上号。
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use aya::maps::{Array, HashMap as AyaHashMap, LpmTrie};
+use aya::Ebpf;
+use log::{info, warn};
+use tokio::sync::Mutex;
+
+use crate::asn;
+use crate::geoip::{self, GeoIpCache, GeoIpSource};
+use crate::rules::{self, Action};
+use crate::sni;
+use crate::Opt;
+
+/// PORT_RULE_* 系列 map 能容纳的规则条数上限，和 eBPF 侧的 map 容量保持一致
+const MAX_PORT_RULES: usize = 64;
+
+/// BLOCKED_ASN 能容纳的 ASN 条数上限，和 eBPF 侧的 map 容量保持一致
+const MAX_BLOCKED_ASN: usize = 64;
+
+/// 一次配置装配的结果摘要，用于重载后打印差异
+#[derive(Debug, Default, Clone)]
+pub struct ConfigSummary {
+    pub config_flags: u32,
+    pub geoip_v4_count: usize,
+    pub geoip_v6_count: usize,
+    pub asn_v4_count: usize,
+    pub asn_v6_count: usize,
+    pub rule_count: usize,
+}
+
+/// 决定是否需要重新拉取 GeoIP/ASN 数据、重建 GEOIP_RULE_MAP{,6}/BLOCKED_ASN_GROUP
+/// 的特征值。不能只记国家/ASN 的*集合*: `rules::assign_group_bits` 给规则级
+/// 国家/ASN 组合分配的 bit 位是按规则在 `effective_rules` 里出现的顺序分配的，
+/// --config 规则文件改了规则顺序或分组、但引用的国家/ASN 集合恰好没变时，
+/// 集合不变但每个国家/ASN 对应的 bit 位会变 —— 这时如果只按集合判断跳过，
+/// PORT_RULE_GEO_GROUP/PORT_RULE_ASN_GROUP 会用新 bit 重新下发，而
+/// GEOIP_RULE_MAP{,6}/BLOCKED_ASN_GROUP 却保留旧 bit，两边对不上号。所以这里
+/// 连同每个国家是否在全局名单里、每个国家/ASN 对应的 bit 掩码一起存下来，
+/// 结构一变这份值就跟着变，自然不会再命中跳过
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GeoAsnSignature {
+    /// (国家代码大写, 是否在全局名单里, 规则作用域 bit 掩码)，按国家代码排序
+    geo_entries: Vec<(String, bool, u32)>,
+    whitelist_mode: bool,
+    /// (ASN 号, 规则作用域 bit 掩码)，按 ASN 号排序
+    asn_entries: Vec<(u32, u32)>,
+}
+
+/// 根据命令行参数计算规则标志位，(可选)拉取 GeoIP/ASN 数据，并写入对应的 eBPF map。
+/// `last_geo_asn` 保存上一次成功装配时的 [`GeoAsnSignature`]，国家/ASN 集合和对应
+/// 的规则作用域 bit 都和上次相同时跳过重新拉取与重建 GeoIP/ASN map(详见本文件
+/// 头部的说明)
+pub async fn apply_config(
+    ebpf: &Arc<Mutex<Ebpf>>,
+    opt: &Opt,
+    last_geo_asn: &Mutex<Option<GeoAsnSignature>>,
+) -> anyhow::Result<ConfigSummary> {
+    let mut target_countries = Vec::new();
+    let mut whitelist_mode = false;
+
+    if !opt.block_all_from.is_empty() {
+        target_countries = opt.block_all_from.clone();
+    } else if !opt.allow_only_countries.is_empty() {
+        target_countries = opt.allow_only_countries.clone();
+        whitelist_mode = true;
+    } else if !opt.countries.is_empty() {
+        target_countries = opt.countries.clone();
+    }
+
+    // 合并 --config 规则文件(如果有)和旧 flag 展开出的规则，这份列表统一驱动
+    // 下面的 config_flags 计算和 PORT_RULES 编译
+    let mut effective_rules = match &opt.config {
+        Some(path) => rules::load(path).context("加载规则文件失败")?,
+        None => Vec::new(),
+    };
+    effective_rules.extend(rules::desugar_from_opt(opt));
+    rules::validate(&effective_rules);
+
+    // 给每条规则的 countries/asn 分配 bit 位，列表完全相同的规则复用同一位，
+    // 留空则固定是 0(不按这个维度限定)，详见 rules::assign_group_bits
+    let geo_bits =
+        rules::assign_group_bits(effective_rules.iter().map(|rule| rule.countries.as_slice()));
+    let asn_bits =
+        rules::assign_group_bits(effective_rules.iter().map(|rule| rule.asn.as_slice()));
+
+    let mut config_flags: u32 = effective_rules
+        .iter()
+        .fold(0u32, |acc, rule| acc | rules::protocol_flag(&rule.protocol));
+    if !target_countries.is_empty() {
+        config_flags |= rfw_common::RULE_GEOIP_ENABLED;
+        if whitelist_mode {
+            config_flags |= rfw_common::RULE_GEOIP_WHITELIST;
+        }
+    }
+    if effective_rules
+        .iter()
+        .any(|rule| rule.action == Action::Log)
+    {
+        config_flags |= rfw_common::RULE_LOG_ONLY;
+    }
+
+    let mut summary = ConfigSummary {
+        config_flags,
+        rule_count: effective_rules.len().min(MAX_PORT_RULES),
+        ..Default::default()
+    };
+
+    // --countries/--allow-only-countries 之外，某条规则自己指定的国家也要一并
+    // 拉取 GeoIP 数据，否则这条规则的国家作用域无从生效
+    let mut geo_countries = target_countries.clone();
+    for rule in &effective_rules {
+        for country in &rule.countries {
+            if !geo_countries.iter().any(|c| c.eq_ignore_ascii_case(country)) {
+                geo_countries.push(country.clone());
+            }
+        }
+    }
+
+    let mut geo_sig_countries: Vec<String> =
+        geo_countries.iter().map(|c| c.to_uppercase()).collect();
+    geo_sig_countries.sort();
+    geo_sig_countries.dedup();
+
+    // 国家代码(大写) -> 引用了它的规则的 bit 位，用来填 GEOIP_RULE_MAP{,6}。和
+    // GeoIP 数据本身的拉取无关，只是字符串/位运算，提前算出来判断能不能跳过重拉取
+    let mut rule_country_bits: HashMap<String, u32> = HashMap::new();
+    for (rule, &bit) in effective_rules.iter().zip(&geo_bits) {
+        if bit == 0 {
+            continue;
+        }
+        for country in &rule.countries {
+            *rule_country_bits.entry(country.to_uppercase()).or_insert(0) |= bit;
+        }
+    }
+    let target_set: HashSet<String> = target_countries.iter().map(|c| c.to_uppercase()).collect();
+
+    // 不能只比较国家集合: 同一个集合下规则的 bit 分配可能因为 --config 规则顺序
+    // /分组变化而变化，所以把每个国家是否在全局名单里、对应的规则 bit 都编进签名
+    let geo_entries: Vec<(String, bool, u32)> = geo_sig_countries
+        .iter()
+        .map(|country| {
+            (
+                country.clone(),
+                target_set.contains(country),
+                rule_country_bits.get(country).copied().unwrap_or(0),
+            )
+        })
+        .collect();
+
+    let previous_signature = last_geo_asn.lock().await.clone();
+    let geo_unchanged = previous_signature.as_ref().is_some_and(|sig| {
+        sig.geo_entries == geo_entries && sig.whitelist_mode == whitelist_mode
+    });
+
+    if !geo_countries.is_empty() && geo_unchanged {
+        info!(
+            "GeoIP 国家列表与规则作用域和上次装配一致({:?})，跳过重新拉取与重建 map",
+            geo_sig_countries
+        );
+    } else if !geo_countries.is_empty() {
+        let geoip_source = GeoIpSource::parse(&opt.geoip_source, opt.geoip_path.clone())?;
+        let geoip_cache = GeoIpCache {
+            dir: opt.geoip_cache_dir.clone(),
+            ttl: Duration::from_secs(opt.geoip_cache_ttl),
+            refresh: opt.geoip_refresh,
+        };
+        let geo_data_list =
+            geoip::load_geoip_sources(&geoip_source, &geo_countries, &geoip_cache)
+                .await
+                .context("加载 GeoIP 数据失败")?;
+
+        let mut guard = ebpf.lock().await;
+        // GEOIP_MAP{,6}: --countries/--allow-only-countries 的全局名单，语义和
+        // 之前完全一样(value 是成员标志位，不是 bit 掩码)，白名单/黑名单判断只
+        // 看这张表，不受下面规则级作用域的影响
+        let mut geoip_map: LpmTrie<_, u32, u8> = guard.map_mut("GEOIP_MAP").unwrap().try_into()?;
+        let mut geoip_map6: LpmTrie<_, u128, u8> =
+            guard.map_mut("GEOIP_MAP6").unwrap().try_into()?;
+        // GEOIP_RULE_MAP{,6}: 每条规则自己的国家作用域，value 是 bit 掩码，
+        // 和 PORT_RULE_GEO_GROUP 按位与即可判断命中的国家是否在这条规则范围内
+        let mut geoip_rule_map: LpmTrie<_, u32, u32> =
+            guard.map_mut("GEOIP_RULE_MAP").unwrap().try_into()?;
+        let mut geoip_rule_map6: LpmTrie<_, u128, u32> =
+            guard.map_mut("GEOIP_RULE_MAP6").unwrap().try_into()?;
+
+        // 重新加载前先清空旧数据，保证这是一次完整替换而不是叠加
+        let stale_v4: Vec<_> = geoip_map
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale_v4 {
+            let _ = geoip_map.remove(key);
+        }
+        let stale_v6: Vec<_> = geoip_map6
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale_v6 {
+            let _ = geoip_map6.remove(key);
+        }
+        let stale_rule_v4: Vec<_> = geoip_rule_map
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale_rule_v4 {
+            let _ = geoip_rule_map.remove(key);
+        }
+        let stale_rule_v6: Vec<_> = geoip_rule_map6
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale_rule_v6 {
+            let _ = geoip_rule_map6.remove(key);
+        }
+
+        for (country_code, geo_data) in geo_data_list {
+            let in_global_list = target_set.contains(&country_code);
+            let rule_bits = rule_country_bits.get(&country_code).copied().unwrap_or(0);
+            if !in_global_list && rule_bits == 0 {
+                continue;
+            }
+            for rule in &geo_data.rules {
+                for cidr in &rule.ip_cidr {
+                    if cidr.contains(':') {
+                        if let Some((ip, prefix_len)) = geoip::parse_cidr6_to_lpm(cidr) {
+                            let key = aya::maps::lpm_trie::Key::new(prefix_len, ip.to_be());
+                            if in_global_list && geoip_map6.insert(&key, 1, 0).is_ok() {
+                                summary.geoip_v6_count += 1;
+                            }
+                            if rule_bits != 0 {
+                                let _ = geoip_rule_map6.insert(&key, rule_bits, 0);
+                            }
+                        }
+                    } else if let Some((ip, prefix_len)) = geoip::parse_cidr_to_lpm(cidr) {
+                        let key = aya::maps::lpm_trie::Key::new(prefix_len, ip.to_be());
+                        if in_global_list && geoip_map.insert(&key, 1, 0).is_ok() {
+                            summary.geoip_v4_count += 1;
+                        }
+                        if rule_bits != 0 {
+                            let _ = geoip_rule_map.insert(&key, rule_bits, 0);
+                        }
+                    }
+                }
+            }
+            info!("已(重新)加载 {} 的 GeoIP 前缀", country_code);
+        }
+    }
+
+    let needs_asn = asn_bits.iter().any(|&bit| bit != 0);
+    let mut asn_entries: Vec<(u32, u32)> = Vec::new();
+    let mut asn_id_bits: HashMap<u32, u32> = HashMap::new();
+    if needs_asn {
+        // ASN 号 -> 引用了它的规则的 bit 位，和 BLOCKED_ASN_GROUP 按索引一一对应。
+        // 这一步只是字符串解析，不碰网络/map，先算出来决定要不要跳过下面的重拉取
+        for (rule, &bit) in effective_rules.iter().zip(&asn_bits) {
+            if bit == 0 {
+                continue;
+            }
+            for asn_id in asn::parse_blocked_asn_list(&rule.asn)? {
+                *asn_id_bits.entry(asn_id).or_insert(0) |= bit;
+            }
+        }
+        if asn_id_bits.len() > MAX_BLOCKED_ASN {
+            warn!(
+                "不同的 ASN 数量超过 BLOCKED_ASN 表容量上限 {}, 多余的 ASN 被忽略",
+                MAX_BLOCKED_ASN
+            );
+        }
+        // 不能只比较 ASN 集合，同样的原因(见 GeoAsnSignature 文档)要连 bit 一起比
+        asn_entries = asn_id_bits.iter().map(|(id, bits)| (*id, *bits)).collect();
+        asn_entries.sort_by_key(|(id, _)| *id);
+    }
+
+    let asn_unchanged = needs_asn
+        && previous_signature
+            .as_ref()
+            .is_some_and(|sig| sig.asn_entries == asn_entries);
+
+    if needs_asn && asn_unchanged {
+        info!("ASN 屏蔽列表与上次装配一致，跳过重新拉取 ASN 数据库与重建 map");
+    } else if needs_asn {
+        let asn_mmdb_path = opt.asn_mmdb.clone().context(
+            "按 ASN 过滤需要同时指定 --asn-mmdb 数据库路径(--block-asn 或规则文件里的 asn 字段)",
+        )?;
+        let prefixes = asn::load_asn_prefixes(&asn_mmdb_path)?;
+
+        let mut guard = ebpf.lock().await;
+        let mut blocked_asn_map: Array<_, u32> =
+            guard.map_mut("BLOCKED_ASN").unwrap().try_into()?;
+        let mut blocked_asn_group_map: Array<_, u32> =
+            guard.map_mut("BLOCKED_ASN_GROUP").unwrap().try_into()?;
+        let mut blocked_asn_count: Array<_, u32> =
+            guard.map_mut("BLOCKED_ASN_COUNT").unwrap().try_into()?;
+
+        // 先清空整张表再整体替换，不能只覆盖 0..n 这一段：否则上一次重载里
+        // 留下的、这次没再写到的尾部条目会继续当作"已屏蔽 ASN"生效
+        for i in 0..MAX_BLOCKED_ASN as u32 {
+            blocked_asn_map.set(i, 0, 0)?;
+            blocked_asn_group_map.set(i, 0, 0)?;
+        }
+        let mut written = 0u32;
+        for (asn_id, bits) in asn_id_bits.iter() {
+            if written as usize >= MAX_BLOCKED_ASN {
+                break;
+            }
+            blocked_asn_map.set(written, *asn_id, 0)?;
+            blocked_asn_group_map.set(written, *bits, 0)?;
+            written += 1;
+        }
+        blocked_asn_count.set(0, written, 0)?;
+
+        let mut asn_map: LpmTrie<_, u32, u32> = guard.map_mut("ASN_MAP").unwrap().try_into()?;
+        let stale_asn: Vec<_> = asn_map
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale_asn {
+            let _ = asn_map.remove(key);
+        }
+        for (network_ip, prefix_len, owning_asn) in &prefixes.v4 {
+            let key = aya::maps::lpm_trie::Key::new(*prefix_len, network_ip.to_be());
+            if asn_map.insert(&key, *owning_asn, 0).is_ok() {
+                summary.asn_v4_count += 1;
+            }
+        }
+
+        let mut asn_map6: LpmTrie<_, u128, u32> =
+            guard.map_mut("ASN_MAP6").unwrap().try_into()?;
+        let stale_asn6: Vec<_> = asn_map6
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale_asn6 {
+            let _ = asn_map6.remove(key);
+        }
+        for (network_ip, prefix_len, owning_asn) in &prefixes.v6 {
+            let key = aya::maps::lpm_trie::Key::new(*prefix_len, network_ip.to_be());
+            if asn_map6.insert(&key, *owning_asn, 0).is_ok() {
+                summary.asn_v6_count += 1;
+            }
+        }
+    }
+
+    {
+        let mut guard = ebpf.lock().await;
+        let mut proto_map: Array<_, u32> = guard.map_mut("PORT_RULE_PROTO").unwrap().try_into()?;
+        let mut range_map: Array<_, u64> = guard.map_mut("PORT_RULE_RANGE").unwrap().try_into()?;
+        let mut action_map: Array<_, u32> =
+            guard.map_mut("PORT_RULE_ACTION").unwrap().try_into()?;
+        let mut direction_map: Array<_, u32> =
+            guard.map_mut("PORT_RULE_DIRECTION").unwrap().try_into()?;
+        let mut geo_group_map: Array<_, u32> =
+            guard.map_mut("PORT_RULE_GEO_GROUP").unwrap().try_into()?;
+        let mut asn_group_map: Array<_, u32> =
+            guard.map_mut("PORT_RULE_ASN_GROUP").unwrap().try_into()?;
+        let mut count_map: Array<_, u32> = guard.map_mut("PORT_RULE_COUNT").unwrap().try_into()?;
+
+        let mut written = 0u32;
+        for ((rule, &geo_bit), &asn_bit) in effective_rules.iter().zip(&geo_bits).zip(&asn_bits) {
+            if written as usize >= MAX_PORT_RULES {
+                warn!(
+                    "规则数量超过 PORT_RULES 表容量上限 {}, 多余的规则被忽略",
+                    MAX_PORT_RULES
+                );
+                break;
+            }
+            let (port_start, port_end) = match &rule.port {
+                Some(spec) => rules::parse_port_range(spec)?,
+                None => (0u16, 65535u16),
+            };
+            let action_code: u32 = match rule.action {
+                Action::Drop => 0,
+                Action::Pass => 1,
+                Action::Log => 2,
+            };
+
+            proto_map.set(written, rules::protocol_flag(&rule.protocol), 0)?;
+            range_map.set(written, ((port_start as u64) << 16) | port_end as u64, 0)?;
+            action_map.set(written, action_code, 0)?;
+            direction_map.set(written, rules::direction_code(rule.direction), 0)?;
+            geo_group_map.set(written, geo_bit, 0)?;
+            asn_group_map.set(written, asn_bit, 0)?;
+            written += 1;
+        }
+        count_map.set(0, written, 0)?;
+    }
+
+    let sni_suffixes: Vec<&String> = effective_rules
+        .iter()
+        .filter(|rule| rule.protocol == "tls-sni")
+        .flat_map(|rule| rule.sni_suffixes.iter())
+        .collect();
+    if !sni_suffixes.is_empty() {
+        let mut guard = ebpf.lock().await;
+        let mut sni_map: AyaHashMap<_, u64, u8> =
+            guard.map_mut("TLS_SNI_SUFFIXES").unwrap().try_into()?;
+
+        // 和 GeoIP/ASN 一样，先清空旧数据再整体替换
+        let stale: Vec<_> = sni_map
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale {
+            let _ = sni_map.remove(key);
+        }
+
+        for suffix in sni_suffixes {
+            let _ = sni_map.insert(sni::hash_suffix(suffix), 1, 0);
+        }
+    }
+
+    {
+        let mut guard = ebpf.lock().await;
+        let mut config_map: Array<_, u32> = guard.map_mut("CONFIG").unwrap().try_into()?;
+        config_map.set(0, config_flags, 0)?;
+    }
+
+    // 这次装配成功了，把 GeoIP/ASN 特征值存下来，供下一次调用判断是否可以跳过
+    *last_geo_asn.lock().await = Some(GeoAsnSignature {
+        geo_entries,
+        whitelist_mode,
+        asn_entries,
+    });
+
+    Ok(summary)
+}
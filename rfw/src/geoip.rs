@@ -0,0 +1,384 @@
+// GeoIP 数据获取与缓存
+//
+// 支持三种数据源（对应 `--geoip-source`）：
+// - remote: 在线下载 sing-box 规则集 JSON（默认，旧行为）
+// - local:  本地 JSON 规则文件/目录（.dat 文件不被接受，需先转换为 JSON）
+// - mmdb:   MaxMind GeoLite2-Country 风格的 .mmdb 数据库，按 wantedList 国家提取
+//
+// 三种来源最终都归一化为 `GeoIpData`，并支持将远程下载结果缓存到磁盘（`--geoip-cache-dir` /
+// `--geoip-cache-ttl`），离线或 GitHub 不可达时自动回退到缓存。
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context as _;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+// GeoIP 数据JSON结构
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpData {
+    pub rules: Vec<GeoIpRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpRule {
+    pub ip_cidr: Vec<String>,
+}
+
+/// GeoIP 数据来源
+#[derive(Debug, Clone)]
+pub enum GeoIpSource {
+    /// 远程 sing-box 规则集 JSON（默认，按国家逐个下载）
+    Remote,
+    /// 本地 JSON 规则文件或目录（.dat 暂不支持，见 [`load_local_geoip_data`]）
+    Local(PathBuf),
+    /// MaxMind .mmdb 数据库，按国家代码提取
+    Mmdb(PathBuf),
+}
+
+impl GeoIpSource {
+    /// 根据 `--geoip-source` / `--geoip-path` 构造数据源
+    pub fn parse(kind: &str, path: Option<PathBuf>) -> anyhow::Result<Self> {
+        match kind.to_lowercase().as_str() {
+            "remote" => Ok(GeoIpSource::Remote),
+            "local" => Ok(GeoIpSource::Local(
+                path.context("--geoip-source local 需要同时指定 --geoip-path")?,
+            )),
+            "mmdb" => Ok(GeoIpSource::Mmdb(
+                path.context("--geoip-source mmdb 需要同时指定 --geoip-path")?,
+            )),
+            other => anyhow::bail!("未知的 GeoIP 数据源 '{}', 可选: remote, local, mmdb", other),
+        }
+    }
+}
+
+/// 缓存配置
+#[derive(Debug, Clone)]
+pub struct GeoIpCache {
+    pub dir: PathBuf,
+    pub ttl: Duration,
+    pub refresh: bool,
+}
+
+fn cache_file(cache: &GeoIpCache, country_code: &str) -> PathBuf {
+    cache
+        .dir
+        .join(format!("{}.json", country_code.to_lowercase()))
+}
+
+fn is_cache_fresh(path: &Path, ttl: Duration) -> bool {
+    let Ok(meta) = std::fs::metadata(path) else {
+        return false;
+    };
+    let Ok(modified) = meta.modified() else {
+        return false;
+    };
+    SystemTime::now()
+        .duration_since(modified)
+        .map(|age| age < ttl)
+        .unwrap_or(false)
+}
+
+fn load_cache_file(path: &Path) -> anyhow::Result<GeoIpData> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("读取 GeoIP 缓存失败: {}", path.display()))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save_cache_file(path: &Path, data: &GeoIpData) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(data)?)?;
+    Ok(())
+}
+
+// 从 URL 下载并解析指定国家的 GeoIP 数据
+async fn fetch_geoip_data_over_http(country_code: &str) -> anyhow::Result<GeoIpData> {
+    const GEOIP_URL_TEMPLATE: &str = "https://raw.githubusercontent.com/lyc8503/sing-box-rules/refs/heads/rule-set-geoip/geoip-{}.json";
+
+    let url = GEOIP_URL_TEMPLATE.replace("{}", &country_code.to_lowercase());
+    info!(
+        "正在从 {} 下载 {} 的 GeoIP 数据...",
+        url,
+        country_code.to_uppercase()
+    );
+
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+
+    let response = client.get(&url).send().await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "下载 {} 的 GeoIP 数据失败: HTTP {}",
+            country_code,
+            response.status()
+        );
+    }
+
+    let geo_data: GeoIpData = response.json().await?;
+
+    // 统计总的 CIDR 条目数
+    let total_cidrs: usize = geo_data.rules.iter().map(|r| r.ip_cidr.len()).sum();
+    info!(
+        "成功下载并解析 {} 的 {} 个 IP CIDR 前缀",
+        country_code.to_uppercase(),
+        total_cidrs
+    );
+
+    Ok(geo_data)
+}
+
+// 下载单个国家的 GeoIP 数据，优先使用新鲜缓存，下载失败时回退到旧缓存
+async fn fetch_remote_geoip_data(
+    country_code: &str,
+    cache: &GeoIpCache,
+) -> anyhow::Result<GeoIpData> {
+    let cache_path = cache_file(cache, country_code);
+
+    if !cache.refresh && is_cache_fresh(&cache_path, cache.ttl) {
+        if let Ok(data) = load_cache_file(&cache_path) {
+            info!(
+                "使用本地缓存的 {} GeoIP 数据 ({})",
+                country_code.to_uppercase(),
+                cache_path.display()
+            );
+            return Ok(data);
+        }
+    }
+
+    match fetch_geoip_data_over_http(country_code).await {
+        Ok(data) => {
+            if let Err(e) = save_cache_file(&cache_path, &data) {
+                warn!("缓存 {} 的 GeoIP 数据失败: {}", country_code, e);
+            }
+            Ok(data)
+        }
+        Err(e) => {
+            warn!(
+                "下载 {} 的 GeoIP 数据失败: {}, 尝试回退到本地缓存",
+                country_code, e
+            );
+            load_cache_file(&cache_path).context("没有可用的 GeoIP 缓存,且下载失败")
+        }
+    }
+}
+
+// 批量下载多个国家的 GeoIP 数据
+async fn fetch_multiple_geoip_data(
+    country_codes: &[String],
+    cache: &GeoIpCache,
+) -> anyhow::Result<Vec<(String, GeoIpData)>> {
+    let mut results = Vec::new();
+
+    for code in country_codes {
+        let code_upper = code.to_uppercase();
+        match fetch_remote_geoip_data(&code_upper, cache).await {
+            Ok(data) => {
+                results.push((code_upper.clone(), data));
+            }
+            Err(e) => {
+                warn!("获取 {} 的 GeoIP 数据失败: {}", code_upper, e);
+                // 继续处理其他国家,不中断
+            }
+        }
+    }
+
+    if results.is_empty() {
+        anyhow::bail!("所有国家的 GeoIP 数据下载均失败");
+    }
+
+    Ok(results)
+}
+
+// 从本地文件/目录加载 GeoIP 数据，沿用与远程相同的 sing-box JSON 结构
+//
+// .dat（v2ray geoip.dat 的 protobuf 格式）暂不支持直接解析，需先用上游工具转换为 JSON。
+fn load_local_geoip_data(
+    path: &Path,
+    countries: &[String],
+) -> anyhow::Result<Vec<(String, GeoIpData)>> {
+    if path.extension().and_then(|e| e.to_str()) == Some("dat") {
+        anyhow::bail!(
+            "暂不支持直接解析 v2ray geoip.dat (protobuf) 格式，请先转换为 sing-box JSON 格式: {}",
+            path.display()
+        );
+    }
+
+    let mut results = Vec::new();
+    for code in countries {
+        let code_upper = code.to_uppercase();
+        let file = if path.is_dir() {
+            path.join(format!("geoip-{}.json", code.to_lowercase()))
+        } else {
+            path.to_path_buf()
+        };
+
+        let content = std::fs::read_to_string(&file)
+            .with_context(|| format!("读取本地 GeoIP 文件失败: {}", file.display()))?;
+        let data: GeoIpData = serde_json::from_str(&content)
+            .with_context(|| format!("解析本地 GeoIP 文件失败: {}", file.display()))?;
+
+        info!(
+            "已从本地文件加载 {} 的 GeoIP 数据: {}",
+            code_upper,
+            file.display()
+        );
+        results.push((code_upper, data));
+    }
+
+    if results.is_empty() {
+        anyhow::bail!("本地 GeoIP 数据源未产生任何国家数据");
+    }
+
+    Ok(results)
+}
+
+// 从 MaxMind .mmdb 数据库按国家代码（wantedList）提取 CIDR 列表
+fn load_mmdb_geoip_data(
+    path: &Path,
+    countries: &[String],
+) -> anyhow::Result<Vec<(String, GeoIpData)>> {
+    let reader = maxminddb::Reader::open_readfile(path)
+        .with_context(|| format!("打开 MaxMind 数据库失败: {}", path.display()))?;
+
+    let wanted: HashSet<String> = countries.iter().map(|c| c.to_uppercase()).collect();
+    let mut by_country: HashMap<String, Vec<String>> = HashMap::new();
+
+    let all_v4: ipnetwork::IpNetwork = "0.0.0.0/0".parse().unwrap();
+    for item in reader.within::<maxminddb::geoip2::Country>(all_v4)? {
+        let item = item?;
+        if let Some(code) = item.info.country.and_then(|c| c.iso_code) {
+            let code = code.to_uppercase();
+            if wanted.contains(&code) {
+                by_country
+                    .entry(code)
+                    .or_default()
+                    .push(item.ip_net.to_string());
+            }
+        }
+    }
+
+    let all_v6: ipnetwork::IpNetwork = "::/0".parse().unwrap();
+    for item in reader.within::<maxminddb::geoip2::Country>(all_v6)? {
+        let item = item?;
+        if let Some(code) = item.info.country.and_then(|c| c.iso_code) {
+            let code = code.to_uppercase();
+            if wanted.contains(&code) {
+                by_country
+                    .entry(code)
+                    .or_default()
+                    .push(item.ip_net.to_string());
+            }
+        }
+    }
+
+    let results: Vec<(String, GeoIpData)> = by_country
+        .into_iter()
+        .map(|(code, cidrs)| {
+            let total = cidrs.len();
+            info!("已从 mmdb 提取 {} 的 {} 个 IP 前缀", code, total);
+            (
+                code,
+                GeoIpData {
+                    rules: vec![GeoIpRule { ip_cidr: cidrs }],
+                },
+            )
+        })
+        .collect();
+
+    if results.is_empty() {
+        anyhow::bail!("mmdb 数据库中未找到指定国家 {:?} 的任何前缀", countries);
+    }
+
+    Ok(results)
+}
+
+/// 按配置的数据源加载所有目标国家的 GeoIP 数据
+pub async fn load_geoip_sources(
+    source: &GeoIpSource,
+    countries: &[String],
+    cache: &GeoIpCache,
+) -> anyhow::Result<Vec<(String, GeoIpData)>> {
+    match source {
+        GeoIpSource::Remote => fetch_multiple_geoip_data(countries, cache).await,
+        GeoIpSource::Local(path) => load_local_geoip_data(path, countries),
+        GeoIpSource::Mmdb(path) => load_mmdb_geoip_data(path, countries),
+    }
+}
+
+// 解析 CIDR 格式（如 "1.0.1.0/24"）为 LpmTrie 的 (IP, prefix_len)
+pub fn parse_cidr_to_lpm(cidr: &str) -> Option<(u32, u32)> {
+    let parts: Vec<&str> = cidr.split('/').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+
+    // 解析 IP 地址
+    let ip_parts: Vec<&str> = parts[0].split('.').collect();
+    if ip_parts.len() != 4 {
+        return None;
+    }
+
+    let ip: u32 = ip_parts
+        .iter()
+        .enumerate()
+        .try_fold(0u32, |acc, (i, &part)| {
+            part.parse::<u8>()
+                .ok()
+                .map(|byte| acc | ((byte as u32) << (24 - i * 8)))
+        })?;
+
+    // 解析前缀长度
+    let prefix_len: u32 = parts[1].parse().ok()?;
+    if prefix_len > 32 {
+        return None;
+    }
+
+    // 计算网络掩码
+    let mask = if prefix_len == 0 {
+        0u32
+    } else {
+        !0u32 << (32 - prefix_len)
+    };
+
+    // 计算网络地址（应用掩码）
+    let network_ip = ip & mask;
+
+    // 返回网络地址和前缀长度
+    Some((network_ip, prefix_len))
+}
+
+// 解析 IPv6 CIDR 格式（如 "2001:db8::/32"）为 LpmTrie 的 (IP, prefix_len)
+pub fn parse_cidr6_to_lpm(cidr: &str) -> Option<(u128, u32)> {
+    let parts: Vec<&str> = cidr.split('/').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+
+    let addr: std::net::Ipv6Addr = parts[0].parse().ok()?;
+    let ip = u128::from_be_bytes(addr.octets());
+
+    // 解析前缀长度
+    let prefix_len: u32 = parts[1].parse().ok()?;
+    if prefix_len > 128 {
+        return None;
+    }
+
+    // 计算网络掩码
+    let mask = if prefix_len == 0 {
+        0u128
+    } else {
+        !0u128 << (128 - prefix_len)
+    };
+
+    // 计算网络地址（应用掩码）
+    let network_ip = ip & mask;
+
+    // 返回网络地址和前缀长度
+    Some((network_ip, prefix_len))
+}
@@ -1,116 +1,25 @@
+mod asn;
+mod ban;
+mod config;
+mod geoip;
+mod rules;
+mod sni;
+mod stats;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Context as _;
-use aya::maps::{Array, LpmTrie};
 use aya::programs::{Xdp, XdpFlags};
 use clap::Parser;
 #[rustfmt::skip]
 use log::{debug, info, warn};
-use serde::Deserialize;
 use tokio::signal;
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::Mutex;
 
-// GeoIP 数据JSON结构
-#[derive(Debug, Deserialize)]
-struct GeoIpData {
-    rules: Vec<GeoIpRule>,
-}
-
-#[derive(Debug, Deserialize)]
-struct GeoIpRule {
-    ip_cidr: Vec<String>,
-}
-
-// 从 URL 下载并解析指定国家的 GeoIP 数据
-async fn fetch_geoip_data(country_code: &str) -> anyhow::Result<GeoIpData> {
-    const GEOIP_URL_TEMPLATE: &str = "https://raw.githubusercontent.com/lyc8503/sing-box-rules/refs/heads/rule-set-geoip/geoip-{}.json";
-
-    let url = GEOIP_URL_TEMPLATE.replace("{}", &country_code.to_lowercase());
-    info!("正在从 {} 下载 {} 的 GeoIP 数据...", url, country_code.to_uppercase());
-
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()?;
-
-    let response = client.get(&url).send().await?;
-
-    if !response.status().is_success() {
-        anyhow::bail!("下载 {} 的 GeoIP 数据失败: HTTP {}", country_code, response.status());
-    }
-
-    let geo_data: GeoIpData = response.json().await?;
-
-    // 统计总的 CIDR 条目数
-    let total_cidrs: usize = geo_data.rules.iter().map(|r| r.ip_cidr.len()).sum();
-    info!("成功下载并解析 {} 的 {} 个 IP CIDR 前缀", country_code.to_uppercase(), total_cidrs);
-
-    Ok(geo_data)
-}
-
-// 批量下载多个国家的 GeoIP 数据
-async fn fetch_multiple_geoip_data(country_codes: &[String]) -> anyhow::Result<Vec<(String, GeoIpData)>> {
-    let mut results = Vec::new();
-
-    for code in country_codes {
-        let code_upper = code.to_uppercase();
-        match fetch_geoip_data(&code_upper).await {
-            Ok(data) => {
-                results.push((code_upper.clone(), data));
-            }
-            Err(e) => {
-                warn!("获取 {} 的 GeoIP 数据失败: {}", code_upper, e);
-                // 继续处理其他国家,不中断
-            }
-        }
-    }
-
-    if results.is_empty() {
-        anyhow::bail!("所有国家的 GeoIP 数据下载均失败");
-    }
-
-    Ok(results)
-}
-
-// 解析 CIDR 格式（如 "1.0.1.0/24"）为 LpmTrie 的 (IP, prefix_len)
-fn parse_cidr_to_lpm(cidr: &str) -> Option<(u32, u32)> {
-    let parts: Vec<&str> = cidr.split('/').collect();
-    if parts.len() != 2 {
-        return None;
-    }
-
-    // 解析 IP 地址
-    let ip_parts: Vec<&str> = parts[0].split('.').collect();
-    if ip_parts.len() != 4 {
-        return None;
-    }
-
-    let ip: u32 = ip_parts
-        .iter()
-        .enumerate()
-        .try_fold(0u32, |acc, (i, &part)| {
-            part.parse::<u8>()
-                .ok()
-                .map(|byte| acc | ((byte as u32) << (24 - i * 8)))
-        })?;
-
-    // 解析前缀长度
-    let prefix_len: u32 = parts[1].parse().ok()?;
-    if prefix_len > 32 {
-        return None;
-    }
-
-    // 计算网络掩码
-    let mask = if prefix_len == 0 {
-        0u32
-    } else {
-        !0u32 << (32 - prefix_len)
-    };
-
-    // 计算网络地址（应用掩码）
-    let network_ip = ip & mask;
-
-    // 返回网络地址和前缀长度
-    Some((network_ip, prefix_len))
-}
-
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 #[clap(
     name = "rfw",
     version,
@@ -251,6 +160,104 @@ struct Opt {
     #[clap(long)]
     block_all: bool,
 
+    /// 屏蔽 Trojan 代理入站连接
+    ///
+    /// 检测 Trojan 协议特征帧: 56 位十六进制字符(password 的 SHA224) + CRLF +
+    /// SOCKS 风格请求 + CRLF，伪装在看起来像 TLS 记录的流量里
+    /// 配合 --countries 限定国家,或不指定则应用于所有流量
+    #[clap(long)]
+    block_trojan: bool,
+
+    /// 屏蔽指定域名后缀的 TLS 连接(逗号分隔,如: example.com,.cn)
+    ///
+    /// 解析 TLS ClientHello(记录类型 0x16, 握手类型 0x01)的 server_name 扩展，
+    /// 提取出的 SNI 域名匹配到任一后缀即丢弃该连接
+    #[clap(long, value_delimiter = ',')]
+    block_tls_sni: Vec<String>,
+
+    /// GeoIP 数据来源
+    ///
+    /// - remote: 在线下载 sing-box 规则集 JSON(默认)
+    /// - local:  本地 JSON 规则文件或目录,配合 --geoip-path 使用
+    /// - mmdb:   MaxMind GeoLite2-Country 风格的 .mmdb 数据库,配合 --geoip-path 使用
+    #[clap(long, default_value = "remote")]
+    geoip_source: String,
+
+    /// 配合 --geoip-source local/mmdb 使用，本地数据文件或目录路径
+    #[clap(long)]
+    geoip_path: Option<PathBuf>,
+
+    /// GeoIP 规则下载缓存目录
+    ///
+    /// 首次下载后缓存到此目录，之后在 TTL 内的运行直接读取缓存，
+    /// 下载失败(如离线/GitHub 不可达)时也会自动回退到缓存
+    #[clap(long, default_value = "/var/cache/rfw/geoip")]
+    geoip_cache_dir: PathBuf,
+
+    /// GeoIP 缓存有效期(秒)，超过后重新下载
+    #[clap(long, default_value_t = 86400)]
+    geoip_cache_ttl: u64,
+
+    /// 强制重新下载 GeoIP 数据，忽略本地缓存
+    #[clap(long)]
+    geoip_refresh: bool,
+
+    /// 屏蔽指定 ASN(自治系统号)的所有入站流量(逗号分隔,如: AS13335,AS32934)
+    ///
+    /// 用于精确屏蔽 Cloudflare/AWS/Facebook 等云/CDN 网段，这些网段通常
+    /// 横跨多个国家，无法用 --countries 精确表达
+    /// 需要配合 --asn-mmdb 指定 GeoLite2-ASN 数据库
+    #[clap(long, value_delimiter = ',')]
+    block_asn: Vec<String>,
+
+    /// GeoLite2-ASN 风格的 .mmdb 数据库路径，配合 --block-asn 使用
+    #[clap(long)]
+    asn_mmdb: Option<PathBuf>,
+
+    /// 声明式规则文件路径(.yaml/.yml/.json)
+    ///
+    /// 每条规则可组合指定国家/ASN/协议/方向(inbound/outbound/both)/
+    /// 目的端口(范围)/动作(drop/pass/log)，国家/ASN 的作用域按规则独立生效，
+    /// 例如可以表达"屏蔽 CN 的 HTTP 且屏蔽 RU 在 8000-9000 端口段的 SOCKS5"。
+    /// 上面的 --block-http / --block-socks5 等 flag 依然有效，会被展开为等价的
+    /// 规则并与本文件中的规则合并，因此可以混用或只用其中一种方式
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// 自动封禁阈值: 滑动窗口内收到超过该数量的入站 SYN 即封禁来源 IP
+    ///
+    /// 不设置则不启用自动封禁(fail2ban 风格的动态黑名单)
+    #[clap(long)]
+    ban_threshold: Option<u32>,
+
+    /// 自动封禁滑动窗口大小(秒)，配合 --ban-threshold 使用
+    #[clap(long, default_value_t = 60)]
+    ban_window: u64,
+
+    /// 自动封禁的持续时间(秒)，到期后自动解封
+    #[clap(long, default_value_t = 3600)]
+    ban_duration: u64,
+
+    /// 纯日志模式: 只统计各规则命中次数并打印日志，不实际丢包
+    ///
+    /// 用于在真正启用拦截前评估规则的影响范围，推荐流程:
+    /// 先以 --log-only 运行一段时间，观察 STATS 统计和日志中的命中情况，
+    /// 确认符合预期后再去掉 --log-only 正式启用拦截
+    #[clap(long)]
+    log_only: bool,
+
+    /// 纯日志模式下打印命中统计表的间隔(秒)
+    #[clap(long, default_value_t = 5)]
+    stats_interval: u64,
+
+    /// 流重组表(FLOW_TABLE)容量: 可同时跟踪的并发连接数上限
+    ///
+    /// 协议检测器(HTTP/SOCKS5/WireGuard/QUIC/FET)按 {源IP, 目的IP, 源端口, 目的端口}
+    /// 四元组重组每条连接前若干字节的 payload 后再统一判定，避免握手被拆分到
+    /// 多个包中而漏检。超过容量后按 LRU 淘汰最久未使用的连接
+    #[clap(long, default_value_t = 4096)]
+    flow_table_size: u32,
+
     /// XDP 附加模式
     ///
     /// - auto: 自动选择最佳模式(默认)
@@ -302,8 +309,19 @@ async fn main() -> anyhow::Result<()> {
     }
 
     // 检查是否至少启用了一个规则
-    if !opt.block_email && !opt_http && !opt_socks5 && !opt_fet_strict
-       && !opt_fet_loose && !opt_wg && !opt_quic && !opt_all
+    if !opt.block_email
+        && !opt_http
+        && !opt_socks5
+        && !opt_fet_strict
+        && !opt_fet_loose
+        && !opt_wg
+        && !opt_quic
+        && !opt_all
+        && !opt.block_trojan
+        && opt.block_tls_sni.is_empty()
+        && opt.block_asn.is_empty()
+        && opt.config.is_none()
+        && opt.ban_threshold.is_none()
     {
         println!("警告: 未启用任何防火墙规则，程序将运行但不执行任何过滤操作");
         println!("使用 'rfw --help' 查看可用规则列表");
@@ -324,10 +342,15 @@ async fn main() -> anyhow::Result<()> {
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
-    let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/rfw"
-    )))?;
+    //
+    // FLOW_TABLE (跨包流重组用的 LRU_HASH) 的容量在加载前按 --flow-table-size 调整，
+    // 这样可以在不重新编译 eBPF 程序的前提下按部署规模收缩/放大并发连接跟踪上限
+    let mut ebpf = aya::EbpfLoader::new()
+        .set_max_entries("FLOW_TABLE", opt.flow_table_size)
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/rfw"
+        )))?;
     match aya_log::EbpfLogger::init(&mut ebpf) {
         Err(e) => {
             // This can happen if you remove all log statements from your eBPF program.
@@ -345,159 +368,104 @@ async fn main() -> anyhow::Result<()> {
             });
         }
     }
-    // 配置防火墙规则
-    let mut config_flags: u32 = 0;
 
+    info!(
+        "协议检测启用跨包流重组,并发连接跟踪上限: {}",
+        opt.flow_table_size
+    );
+
+    // 用 Arc<Mutex<_>> 包装，以便后台的封禁清扫任务和主流程共享同一个 eBPF 实例
+    let ebpf = Arc::new(Mutex::new(ebpf));
+
+    // 启用规则提示(实际的 flags 计算、GeoIP/ASN 加载和写 map 统一在 apply_config 中完成,
+    // 热重载时复用同一份逻辑,见下方 SIGHUP 处理)
     if opt.block_email {
-        config_flags |= rfw_common::RULE_BLOCK_EMAIL;
         info!("启用规则: 屏蔽发送 Email");
     }
-
-    // 如果指定了国家,启用 GeoIP 过滤
-    if !target_countries.is_empty() {
-        config_flags |= rfw_common::RULE_GEOIP_ENABLED;
-        if whitelist_mode {
-            config_flags |= rfw_common::RULE_GEOIP_WHITELIST;
-        }
-    }
-
+    let scope = if target_countries.is_empty() {
+        "所有来源".to_string()
+    } else {
+        format!("{:?} 国家", target_countries)
+    };
     if opt_http {
-        config_flags |= rfw_common::RULE_BLOCK_HTTP;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
         info!("启用规则: 屏蔽 {} 的 HTTP 入站", scope);
     }
-
     if opt_socks5 {
-        config_flags |= rfw_common::RULE_BLOCK_SOCKS5;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
         info!("启用规则: 屏蔽 {} 的 SOCKS5 入站", scope);
     }
-
     if opt_fet_strict {
-        config_flags |= rfw_common::RULE_BLOCK_FET_STRICT;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
-        info!("启用规则: 屏蔽 {} 的全加密流量入站 (严格模式 - 默认阻止)", scope);
+        info!(
+            "启用规则: 屏蔽 {} 的全加密流量入站 (严格模式 - 默认阻止)",
+            scope
+        );
     }
-
     if opt_fet_loose {
-        config_flags |= rfw_common::RULE_BLOCK_FET_LOOSE;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
-        info!("启用规则: 屏蔽 {} 的全加密流量入站 (宽松模式 - 默认放过)", scope);
+        info!(
+            "启用规则: 屏蔽 {} 的全加密流量入站 (宽松模式 - 默认放过)",
+            scope
+        );
     }
-
     if opt_wg {
-        config_flags |= rfw_common::RULE_BLOCK_WIREGUARD;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
         info!("启用规则: 屏蔽 {} 的 WireGuard VPN 入站", scope);
     }
-
     if opt_quic {
-        config_flags |= rfw_common::RULE_BLOCK_QUIC;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
         info!("启用规则: 屏蔽 {} 的 QUIC 入站", scope);
     }
-
     if opt_all {
-        config_flags |= rfw_common::RULE_BLOCK_ALL;
-        let scope = if target_countries.is_empty() {
-            "所有来源".to_string()
-        } else {
-            format!("{:?} 国家", target_countries)
-        };
         info!("启用规则: 屏蔽 {} 的所有入站流量", scope);
     }
+    if opt.block_trojan {
+        info!("启用规则: 屏蔽 {} 的 Trojan 入站", scope);
+    }
+    if !opt.block_tls_sni.is_empty() {
+        info!("启用规则: 屏蔽 TLS SNI 匹配 {:?} 的连接", opt.block_tls_sni);
+    }
+    if !opt.block_asn.is_empty() {
+        info!("启用规则: 屏蔽 ASN {:?}", opt.block_asn);
+    }
+    if opt.log_only {
+        info!(
+            "已启用纯日志模式(--log-only): 规则只计数不拦截，每 {}s 打印一次命中统计",
+            opt.stats_interval
+        );
+    }
 
-    // 将配置写入 eBPF map
-    let mut config_map: Array<_, u32> = ebpf.map_mut("CONFIG").unwrap().try_into()?;
-    config_map.set(0, config_flags, 0)?;
-    info!("防火墙配置已设置: flags = 0x{:x}", config_flags);
-
-    // 如果需要 GeoIP 规则，从网络下载 IP 段数据
-    if !target_countries.is_empty() {
-        info!("检测到需要 GeoIP 规则，正在下载 {:?} 的 IP 数据...", target_countries);
-
-        // 批量下载所有国家的 GeoIP 数据
-        let geo_data_list = fetch_multiple_geoip_data(&target_countries)
-            .await
-            .context("下载 GeoIP 数据失败，请检查网络连接")?;
-
-        // 使用 LpmTrie 进行高效的 IP 前缀匹配
-        let mut geoip_map: LpmTrie<_, u32, u8> = ebpf.map_mut("GEOIP_MAP").unwrap().try_into()?;
-
-        let mut loaded_count = 0;
-        let mut insert_errors = 0;
-
-        // 处理所有国家的数据
-        for (country_code, geo_data) in geo_data_list {
-            info!("正在加载 {} 的 IP 前缀...", country_code);
-
-            for rule in &geo_data.rules {
-                for cidr in &rule.ip_cidr {
-                    // 解析 CIDR（如 "1.0.1.0/24"）
-                    if let Some((ip, prefix_len)) = parse_cidr_to_lpm(cidr) {
-                        // 构造 LpmTrie Key
-                        // 注意：IP地址必须转换为网络字节序（大端）
-                        let key = aya::maps::lpm_trie::Key::new(prefix_len, ip.to_be());
-
-                        // 插入到 LpmTrie，value=1 表示匹配的IP
-                        // 注意: 在当前实现中,所有国家共用同一个 map,value 统一为 1
-                        // 未来可以扩展 value 存储国家代码
-                        if let Err(e) = geoip_map.insert(&key, 1, 0) {
-                            if insert_errors < 5 {
-                                warn!(
-                                    "插入 {} IP 前缀 {} (0x{:08x}/{}) 失败: {}",
-                                    country_code, cidr, ip, prefix_len, e
-                                );
-                            }
-                            insert_errors += 1;
-                        } else {
-                            loaded_count += 1;
-                        }
-                    }
-                }
-            }
-
-            info!("已加载 {} 的 IP 前缀", country_code);
-        }
+    // 记录上一次装配时拉取 GeoIP/ASN 数据用的国家/ASN 特征值，SIGHUP 重载时如果
+    // 特征值没变就跳过重新拉取与重建 map，见 config::apply_config 文档
+    let last_geo_asn: Arc<Mutex<Option<config::GeoAsnSignature>>> = Arc::new(Mutex::new(None));
+
+    let summary = config::apply_config(&ebpf, &opt, &last_geo_asn)
+        .await
+        .context("应用防火墙配置失败")?;
+    info!(
+        "防火墙配置已设置: flags = 0x{:x}, 规则 {} 条, GeoIPv4 {} 条前缀, GeoIPv6 {} 条前缀, ASNv4 {} 条前缀, ASNv6 {} 条前缀",
+        summary.config_flags,
+        summary.rule_count,
+        summary.geoip_v4_count,
+        summary.geoip_v6_count,
+        summary.asn_v4_count,
+        summary.asn_v6_count
+    );
 
-        if insert_errors > 0 {
-            warn!(
-                "共有 {} 个IP前缀插入失败（可能是重复或map已满,最大容量 65536）",
-                insert_errors
-            );
-        }
+    // 如果设置了封禁阈值，下发 BAN_CONFIG 并启动后台清扫任务，让到期的封禁自动解除
+    if let Some(threshold) = opt.ban_threshold {
+        ban::configure(&ebpf, threshold, opt.ban_window, opt.ban_duration).await?;
+        ban::spawn_sweep_task(ebpf.clone(), Duration::from_secs(opt.ban_duration));
+    }
 
-        info!(
-            "成功加载 {} 个 IP 前缀到防火墙 (LpmTrie),覆盖国家: {:?}",
-            loaded_count, target_countries
-        );
+    // 只要最终生效的规则里有任何一条落到 log 动作(全局 --log-only，或者
+    // --config 规则文件里某条规则单独写了 action: log)，内核就会往 STATS 里
+    // 计数，这里就要启动打印任务，否则那些命中只会被计数、没人读出来打印
+    if summary.config_flags & rfw_common::RULE_LOG_ONLY != 0 {
+        stats::spawn_print_task(ebpf.clone(), Duration::from_secs(opt.stats_interval));
     }
 
-    let Opt { iface, xdp_mode, .. } = opt;
+    // 保留一份 Opt 供 SIGHUP 处理器重载时使用
+    let reload_opt = opt.clone();
+
+    let Opt {
+        iface, xdp_mode, ..
+    } = opt;
 
     // 根据用户选择确定 XDP 模式
     let xdp_flags = match xdp_mode.to_lowercase().as_str() {
@@ -523,15 +491,46 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    let program: &mut Xdp = ebpf.program_mut("rfw").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&iface, xdp_flags)
-        .context(format!(
-            "无法以 {} 模式附加 XDP 程序到接口 {}。\n提示: 如果附加失败，请尝试使用 --xdp-mode skb 选项",
-            xdp_mode, iface
-        ))?;
+    {
+        let mut guard = ebpf.lock().await;
+        let program: &mut Xdp = guard.program_mut("rfw").unwrap().try_into()?;
+        program.load()?;
+        program.attach(&iface, xdp_flags)
+            .context(format!(
+                "无法以 {} 模式附加 XDP 程序到接口 {}。\n提示: 如果附加失败，请尝试使用 --xdp-mode skb 选项",
+                xdp_mode, iface
+            ))?;
+    }
 
     info!("XDP 程序已成功附加到接口: {} (模式: {})", iface, xdp_mode);
+
+    // SIGHUP 热重载: 重新计算规则标志位，如果国家/ASN 列表有变化就重新拉取
+    // GeoIP/ASN 数据并重建对应的 map(期间有短暂的丢包窗口，见 config.rs 文档)。
+    // 注意 `reload_opt` 是启动时 `Opt` 的一份快照，纯 CLI flag(不带 --config)
+    // 部署下这份输入永远不变，SIGHUP 重载实际只在读取 --config 指向的规则文件
+    // 发生变化时才有意义
+    let reload_ebpf = ebpf.clone();
+    let reload_last_geo_asn = last_geo_asn.clone();
+    let mut hangup = unix_signal(SignalKind::hangup()).context("无法注册 SIGHUP 信号处理器")?;
+    tokio::task::spawn(async move {
+        loop {
+            hangup.recv().await;
+            info!("收到 SIGHUP,正在热重载规则与 GeoIP/ASN 数据...");
+            match config::apply_config(&reload_ebpf, &reload_opt, &reload_last_geo_asn).await {
+                Ok(summary) => info!(
+                    "热重载完成: flags = 0x{:x}, 规则 {} 条, GeoIPv4 {} 条前缀, GeoIPv6 {} 条前缀, ASNv4 {} 条前缀, ASNv6 {} 条前缀",
+                    summary.config_flags,
+                    summary.rule_count,
+                    summary.geoip_v4_count,
+                    summary.geoip_v6_count,
+                    summary.asn_v4_count,
+                    summary.asn_v6_count
+                ),
+                Err(e) => warn!("热重载失败,继续使用旧配置: {}", e),
+            }
+        }
+    });
+
     let ctrl_c = signal::ctrl_c();
     println!("防火墙运行中，按 Ctrl-C 退出...");
     ctrl_c.await?;
@@ -0,0 +1,290 @@
+// 声明式规则引擎
+//
+// `--config rules.yaml`(或 .json)描述一组可组合的规则，每条规则可选地指定
+// 国家/ASN/协议/方向/目的端口(范围)/动作(drop/pass/log)，取代"一个规则一个 flag"
+// 的写法，支持表达"屏蔽 CN 的 HTTP 且屏蔽 RU 在特定端口段的 SOCKS5"这类组合。
+//
+// 为了不破坏现有用法，所有旧的 --block-http / --block-socks5 等 flag 仍然保留，
+// 在 [`desugar_from_opt`] 中被展开为等价的规则，和 --config 里读到的规则合并后
+// 统一编译。这份合并后的规则列表就是 SIGHUP 热重载(见 config.rs)的重载单元。
+//
+// 每条规则的 countries/asn 字段是真正按规则生效的: [`assign_group_bits`] 给
+// 列表完全相同的规则分配同一个 bit 位(最多 31 个不同组合，按位存进一个 u32)。
+// config.rs 把这些 bit 位写进 GEOIP_RULE_MAP/GEOIP_RULE_MAP6(国家)和
+// BLOCKED_ASN_GROUP(ASN)里，再和每条规则自己的 bit 位一起下发到
+// PORT_RULE_GEO_GROUP/PORT_RULE_ASN_GROUP，XDP 侧按位与即可判断命中的国家/ASN
+// 是否落在这条规则的作用域内。bit 位为 0 表示这条规则不按国家/ASN 限定，匹配
+// 任意来源。全局的 --countries/--allow-only-countries 白名单/黑名单判断仍然
+// 只看 GEOIP_MAP/GEOIP_MAP6，和规则级作用域是两套独立的表，互不影响。
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context as _;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::Opt;
+
+/// 规则命中后的处理动作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// 丢弃匹配的数据包
+    Drop,
+    /// 放行匹配的数据包(用于在规则文件里临时豁免)
+    Pass,
+    /// 不丢包，只计数+打日志，等价于全局 --log-only 但作用域限定在这一条规则
+    Log,
+}
+
+fn default_action() -> Action {
+    Action::Drop
+}
+
+/// 规则匹配的流量方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// 只匹配入站(对端发往本机)流量，绝大多数协议探测规则的默认语义
+    Inbound,
+    /// 只匹配出站(本机发往对端)流量
+    Outbound,
+    /// 不限方向，入站/出站都匹配
+    Both,
+}
+
+fn default_direction() -> Direction {
+    Direction::Both
+}
+
+/// 一条声明式规则
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    /// 匹配的国家代码列表，留空表示不按国家限定
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// 匹配的 ASN 列表(如 "AS13335")，留空表示不按 ASN 限定
+    #[serde(default)]
+    pub asn: Vec<String>,
+    /// 匹配的协议: email/http/socks5/fet-strict/fet-loose/wireguard/quic/all/asn/trojan/tls-sni
+    pub protocol: String,
+    /// 目的端口或端口范围，如 "80" 或 "8000-9000"，留空表示不限端口
+    #[serde(default)]
+    pub port: Option<String>,
+    /// 匹配的流量方向，默认 both(不限方向)
+    #[serde(default = "default_direction")]
+    pub direction: Direction,
+    /// 仅 protocol = "tls-sni" 时使用: 要匹配的域名后缀列表
+    #[serde(default)]
+    pub sni_suffixes: Vec<String>,
+    /// 命中后的动作，默认为 drop
+    #[serde(default = "default_action")]
+    pub action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleFile {
+    rules: Vec<Rule>,
+}
+
+/// 从 `--config` 指定的 YAML/JSON 文件加载规则列表
+pub fn load(path: &Path) -> anyhow::Result<Vec<Rule>> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("读取规则文件失败: {}", path.display()))?;
+
+    let file: RuleFile = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&raw)
+            .with_context(|| format!("解析 YAML 规则文件失败: {}", path.display()))?,
+        Some("json") => serde_json::from_str(&raw)
+            .with_context(|| format!("解析 JSON 规则文件失败: {}", path.display()))?,
+        _ => anyhow::bail!(
+            "不支持的规则文件扩展名: {}, 仅支持 .yaml/.yml/.json",
+            path.display()
+        ),
+    };
+
+    Ok(file.rules)
+}
+
+/// 把旧的逐个 flag(--block-http、--block-socks5 等)展开为等价的规则，
+/// 保证只用 flag、不写 --config 的老用户行为不变
+pub fn desugar_from_opt(opt: &Opt) -> Vec<Rule> {
+    let mut target_countries = Vec::new();
+    if !opt.block_all_from.is_empty() {
+        target_countries = opt.block_all_from.clone();
+    } else if !opt.allow_only_countries.is_empty() {
+        target_countries = opt.allow_only_countries.clone();
+    } else if !opt.countries.is_empty() {
+        target_countries = opt.countries.clone();
+    }
+
+    let action = if opt.log_only {
+        Action::Log
+    } else {
+        Action::Drop
+    };
+
+    // 旧 flag 全部是 XDP ingress 挂载点上的入站检测(见各 flag 的 doc,例如
+    // --block-http 明确标注"仅检测入站流量"),展开时如实标成 Inbound
+    let mut rules = Vec::new();
+    let mut push = |protocol: &str, enabled: bool| {
+        if enabled {
+            rules.push(Rule {
+                countries: target_countries.clone(),
+                asn: Vec::new(),
+                protocol: protocol.to_string(),
+                port: None,
+                direction: Direction::Inbound,
+                sni_suffixes: Vec::new(),
+                action,
+            });
+        }
+    };
+
+    push("email", opt.block_email);
+    push("http", opt.block_http);
+    push("socks5", opt.block_socks5);
+    push("fet-strict", opt.block_fet_strict);
+    push("fet-loose", opt.block_fet_loose);
+    push("wireguard", opt.block_wireguard);
+    push("quic", opt.block_quic);
+    push("all", opt.block_all);
+    push("trojan", opt.block_trojan);
+
+    if !opt.block_asn.is_empty() {
+        rules.push(Rule {
+            countries: Vec::new(),
+            asn: opt.block_asn.clone(),
+            protocol: "asn".to_string(),
+            port: None,
+            direction: Direction::Inbound,
+            sni_suffixes: Vec::new(),
+            action,
+        });
+    }
+
+    if !opt.block_tls_sni.is_empty() {
+        rules.push(Rule {
+            countries: target_countries.clone(),
+            asn: Vec::new(),
+            protocol: "tls-sni".to_string(),
+            port: None,
+            direction: Direction::Inbound,
+            sni_suffixes: opt.block_tls_sni.clone(),
+            action,
+        });
+    }
+
+    rules
+}
+
+/// 把规则的协议名映射成 CONFIG/PORT_RULES 里使用的 bit flag
+pub fn protocol_flag(protocol: &str) -> u32 {
+    match protocol {
+        "email" => rfw_common::RULE_BLOCK_EMAIL,
+        "http" => rfw_common::RULE_BLOCK_HTTP,
+        "socks5" => rfw_common::RULE_BLOCK_SOCKS5,
+        "fet-strict" => rfw_common::RULE_BLOCK_FET_STRICT,
+        "fet-loose" => rfw_common::RULE_BLOCK_FET_LOOSE,
+        "wireguard" => rfw_common::RULE_BLOCK_WIREGUARD,
+        "quic" => rfw_common::RULE_BLOCK_QUIC,
+        "all" => rfw_common::RULE_BLOCK_ALL,
+        "asn" => rfw_common::RULE_BLOCK_ASN,
+        "trojan" => rfw_common::RULE_BLOCK_TROJAN,
+        "tls-sni" => rfw_common::RULE_BLOCK_TLS_SNI,
+        _ => 0,
+    }
+}
+
+/// 解析 "80" 或 "8000-9000" 形式的端口/端口范围
+pub fn parse_port_range(spec: &str) -> anyhow::Result<(u16, u16)> {
+    match spec.split_once('-') {
+        Some((start, end)) => {
+            let start: u16 = start
+                .trim()
+                .parse()
+                .with_context(|| format!("无法解析端口范围起始值: {}", spec))?;
+            let end: u16 = end
+                .trim()
+                .parse()
+                .with_context(|| format!("无法解析端口范围结束值: {}", spec))?;
+            Ok((start, end))
+        }
+        None => {
+            let port: u16 = spec
+                .trim()
+                .parse()
+                .with_context(|| format!("无法解析端口: {}", spec))?;
+            Ok((port, port))
+        }
+    }
+}
+
+/// 对每条规则做一次轻量校验: 协议名是否认识
+pub fn validate(rules: &[Rule]) {
+    const KNOWN_PROTOCOLS: &[&str] = &[
+        "email",
+        "http",
+        "socks5",
+        "fet-strict",
+        "fet-loose",
+        "wireguard",
+        "quic",
+        "all",
+        "asn",
+        "trojan",
+        "tls-sni",
+    ];
+
+    for rule in rules {
+        if !KNOWN_PROTOCOLS.contains(&rule.protocol.as_str()) {
+            warn!(
+                "规则文件中存在未知协议 '{}', 可选: {:?}",
+                rule.protocol, KNOWN_PROTOCOLS
+            );
+        }
+    }
+}
+
+/// 把规则的方向映射成 PORT_RULE_DIRECTION 里使用的编码
+pub fn direction_code(direction: Direction) -> u32 {
+    match direction {
+        Direction::Inbound => 0,
+        Direction::Outbound => 1,
+        Direction::Both => 2,
+    }
+}
+
+/// [`assign_group_bits`] 能分配的不同非空国家/ASN 组合数上限
+pub const MAX_GEO_GROUPS: usize = 31;
+
+/// 给一组"国家列表"/"ASN 列表"分配 bit 位: 完全相同的列表复用同一位，留空的
+/// 列表固定分配到 0(表示不按这个维度限定，XDP 侧据此跳过对应的国家/ASN 检查)。
+/// 最多支持 31 种不同的非空组合，超出的规则会在对应维度上退化为不限定并告警，
+/// 对应的 bit 位和 [`crate::geoip`]/ASN 分组表共享同一个 u32 掩码
+pub fn assign_group_bits<'a>(lists: impl Iterator<Item = &'a [String]>) -> Vec<u32> {
+    let mut seen: Vec<&'a [String]> = Vec::new();
+    lists
+        .map(|list| {
+            if list.is_empty() {
+                return 0u32;
+            }
+            let idx = match seen.iter().position(|l| *l == list) {
+                Some(i) => i,
+                None => {
+                    if seen.len() >= MAX_GEO_GROUPS {
+                        warn!(
+                            "不同的规则级国家/ASN 组合超过上限 {}, 多余组合退化为不限定: {:?}",
+                            MAX_GEO_GROUPS, list
+                        );
+                        return 0u32;
+                    }
+                    seen.push(list);
+                    seen.len() - 1
+                }
+            };
+            1u32 << idx
+        })
+        .collect()
+}
@@ -0,0 +1,19 @@
+// TLS SNI 后缀匹配的哈希辅助
+//
+// --block-tls-sni 传入的域名后缀列表在用户态先做 FNV-1a 哈希，写入 eBPF 的
+// TLS_SNI_SUFFIXES 这张 HashMap；内核侧解析 TLS ClientHello 的 server_name
+// 扩展、按候选后缀逐段哈希后同样查这张表，避免在 BPF 侧做变长字符串比较。
+
+/// 对域名后缀做 FNV-1a 哈希，匹配前统一转小写，和大小写不敏感的域名语义一致
+pub fn hash_suffix(suffix: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    suffix
+        .trim()
+        .to_ascii_lowercase()
+        .bytes()
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
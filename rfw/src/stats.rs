@@ -0,0 +1,63 @@
+// 纯日志模式 (--log-only) 的命中统计
+//
+// 内核侧在 --log-only 开启时对匹配到的规则只计数、不丢包(XDP_PASS 代替
+// XDP_DROP),并通过 aya_log 打印命中的规则与源 IP。用户态这里周期性地把
+// STATS 这张按 CPU 分片的计数表汇总打印成一张表，方便在真正启用拦截前
+// 观察"如果启用会拦掉多少流量"。
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use aya::maps::PerCpuArray;
+use aya::Ebpf;
+use log::info;
+use tokio::sync::Mutex;
+
+/// STATS map 中各规则对应的下标，需要和 eBPF 侧的定义保持一致
+const RULE_NAMES: &[(u32, &str)] = &[
+    (0, "EMAIL"),
+    (1, "HTTP"),
+    (2, "SOCKS5"),
+    (3, "FET"),
+    (4, "WIREGUARD"),
+    (5, "QUIC"),
+    (6, "ALL"),
+    (7, "GEOIP"),
+    (8, "TROJAN"),
+    (9, "TLS_SNI"),
+];
+
+/// 启动后台任务，周期性打印各规则的累计命中次数
+pub fn spawn_print_task(ebpf: Arc<Mutex<Ebpf>>, interval: Duration) {
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = print_once(&ebpf).await {
+                log::warn!("读取命中统计失败: {}", e);
+            }
+        }
+    });
+}
+
+async fn print_once(ebpf: &Arc<Mutex<Ebpf>>) -> anyhow::Result<()> {
+    let mut guard = ebpf.lock().await;
+    let stats: PerCpuArray<_, u64> = guard.map_mut("STATS").unwrap().try_into()?;
+
+    let mut line = String::from("纯日志模式命中统计(若启用拦截会被丢弃的包数):");
+    let mut total = 0u64;
+    for (index, name) in RULE_NAMES {
+        let per_cpu = stats.get(index, 0)?;
+        let count: u64 = per_cpu.iter().sum();
+        total += count;
+        line.push_str(&format!(" {}={}", name, count));
+    }
+
+    if total > 0 {
+        info!("{}", line);
+    } else {
+        log::debug!("{}", line);
+    }
+
+    Ok(())
+}